@@ -0,0 +1,126 @@
+use libc;
+use std::mem;
+use std::ptr;
+use {Configuration, Pointer, VM};
+
+// Wren's `WrenReallocateFn` isn't told the old size of a block being resized or freed, so to
+// track live bytes we have to be the allocator: each block gets an extra `HEADER` bytes in front
+// of it holding its own size, and we hand back the pointer just past that header. `HEADER` is
+// rounded up to `ALIGN` (not just `size_of::<usize>()`) so that offsetting a suitably-aligned
+// `malloc`/`realloc` result by `HEADER` bytes keeps the pointer we hand to Wren aligned too -
+// otherwise callers entitled to assume `max_align_t` alignment (the C standard's guarantee for
+// any `malloc`-family pointer) would get something less.
+const ALIGN: usize = mem::align_of::<libc::max_align_t>();
+const HEADER: usize = ALIGN;
+
+/// Per-VM heap bookkeeping for `Configuration::set_memory_limit`. Stored in `foreign::UserData`.
+pub(crate) struct Limit {
+    max_bytes: usize,
+    bytes_allocated: usize,
+}
+
+unsafe fn header_of(memory: Pointer) -> (Pointer, usize) {
+    let header_ptr = (memory as *mut u8).sub(HEADER) as Pointer;
+    let size = *(header_ptr as *const usize);
+    (header_ptr, size)
+}
+
+extern "C" fn limited_reallocate(memory: Pointer, new_size: usize, user_data: Pointer) -> Pointer {
+    let user_data = unsafe { &mut *(user_data as *mut ::foreign::UserData) };
+    // Unwinding across an `extern "C"` boundary is undefined behavior, so a misconfigured
+    // embedder (this installed without `set_memory_limit` ever having run) fails the allocation
+    // instead of panicking.
+    let limit = match user_data.memory_limit.as_mut() {
+        Some(limit) => limit,
+        None => return ptr::null_mut(),
+    };
+
+    if new_size == 0 {
+        if memory.is_null() {
+            return ptr::null_mut();
+        }
+        let (header_ptr, old_size) = unsafe { header_of(memory) };
+        limit.bytes_allocated -= old_size;
+        unsafe { libc::free(header_ptr) };
+        return ptr::null_mut();
+    }
+
+    let old_size = if memory.is_null() {
+        0
+    } else {
+        unsafe { header_of(memory).1 }
+    };
+    let new_total = limit.bytes_allocated - old_size + new_size;
+    if limit.max_bytes > 0 && new_total > limit.max_bytes {
+        return ptr::null_mut();
+    }
+
+    let header_ptr = if memory.is_null() {
+        unsafe { libc::malloc(HEADER + new_size) }
+    } else {
+        let (header_ptr, _) = unsafe { header_of(memory) };
+        unsafe { libc::realloc(header_ptr, HEADER + new_size) }
+    };
+    if header_ptr.is_null() {
+        return ptr::null_mut();
+    }
+
+    unsafe { *(header_ptr as *mut usize) = new_size };
+    limit.bytes_allocated = new_total;
+    unsafe { (header_ptr as *mut u8).add(HEADER) as Pointer }
+}
+
+/// Allocates a header-prefixed, NUL-terminated copy of `bytes` and tracks it against `limit`
+/// exactly like `limited_reallocate` would for a fresh block. Lets code outside this module (see
+/// `vm::resolve_module_shim`) hand Wren a pointer it can later free through `reallocate_fn` once
+/// `Configuration::set_memory_limit` has installed that allocator - a plain `libc::malloc` result
+/// has no header for `limited_reallocate` to read back. Returns null if the allocation would push
+/// `limit` over its cap, or on allocation failure.
+pub(crate) fn alloc_with_header(limit: &mut Limit, bytes: &[u8]) -> Pointer {
+    let new_size = bytes.len() + 1;
+    let new_total = limit.bytes_allocated + new_size;
+    if limit.max_bytes > 0 && new_total > limit.max_bytes {
+        return ptr::null_mut();
+    }
+
+    let header_ptr = unsafe { libc::malloc(HEADER + new_size) };
+    if header_ptr.is_null() {
+        return ptr::null_mut();
+    }
+
+    unsafe {
+        *(header_ptr as *mut usize) = new_size;
+        let data = (header_ptr as *mut u8).add(HEADER);
+        ptr::copy_nonoverlapping(bytes.as_ptr(), data, bytes.len());
+        *data.add(bytes.len()) = 0;
+    }
+    limit.bytes_allocated = new_total;
+    unsafe { (header_ptr as *mut u8).add(HEADER) as Pointer }
+}
+
+impl Configuration {
+    /// Caps the VM's heap at `max_bytes`, enforced by a crate-provided `reallocate_fn`: once a
+    /// request would push the live total over the cap, Wren is handed a null pointer and reports
+    /// out-of-memory/aborts the fiber instead of letting the process grow unbounded. Useful when
+    /// running untrusted scripts. Pass `0` for no limit (still enables `VM::bytes_allocated`).
+    pub fn set_memory_limit(&mut self, max_bytes: usize) {
+        self.ensure_user_data().memory_limit = Some(Limit {
+            max_bytes,
+            bytes_allocated: 0,
+        });
+        self.set_reallocate_fn(Some(limited_reallocate));
+    }
+}
+
+impl VM {
+    /// The number of bytes currently tracked as live by the allocator installed through
+    /// `Configuration::set_memory_limit`.
+    pub fn bytes_allocated(&mut self) -> usize {
+        let data = self.get_user_data() as *mut ::foreign::UserData;
+        unsafe { &*data }
+            .memory_limit
+            .as_ref()
+            .map(|limit| limit.bytes_allocated)
+            .unwrap_or(0)
+    }
+}
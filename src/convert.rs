@@ -0,0 +1,160 @@
+use {Handle, VM};
+
+/// Converts a Rust value into a Wren slot. Implemented for the types `VM::set_slot` accepts.
+pub trait ToWren {
+    fn to_wren(self, vm: &mut VM, slot: i32);
+}
+
+/// Converts a Wren slot into a Rust value. Implemented for the types `VM::get_slot` can produce.
+///
+/// Returns `None` if the slot doesn't hold a value of type `Self`.
+pub trait FromWren: Sized {
+    fn from_wren(vm: &mut VM, slot: i32) -> Option<Self>;
+}
+
+impl ToWren for bool {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_bool(slot, self);
+    }
+}
+
+impl FromWren for bool {
+    fn from_wren(vm: &mut VM, slot: i32) -> Option<bool> {
+        vm.get_slot_bool(slot)
+    }
+}
+
+impl ToWren for f64 {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_double(slot, self);
+    }
+}
+
+impl FromWren for f64 {
+    fn from_wren(vm: &mut VM, slot: i32) -> Option<f64> {
+        vm.get_slot_double(slot)
+    }
+}
+
+impl ToWren for &str {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_string(slot, self);
+    }
+}
+
+impl ToWren for String {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_string(slot, &self);
+    }
+}
+
+impl FromWren for String {
+    fn from_wren(vm: &mut VM, slot: i32) -> Option<String> {
+        vm.get_slot_string(slot).map(|s| s.to_owned())
+    }
+}
+
+impl ToWren for &[u8] {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_bytes(slot, self);
+    }
+}
+
+impl ToWren for Vec<u8> {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_bytes(slot, &self);
+    }
+}
+
+impl FromWren for Vec<u8> {
+    fn from_wren(vm: &mut VM, slot: i32) -> Option<Vec<u8>> {
+        vm.get_slot_bytes(slot).map(|b| b.to_vec())
+    }
+}
+
+impl ToWren for Handle {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_handle(slot, &self);
+    }
+}
+
+impl FromWren for Handle {
+    fn from_wren(vm: &mut VM, slot: i32) -> Option<Handle> {
+        Some(vm.get_slot_handle(slot))
+    }
+}
+
+impl ToWren for () {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_null(slot);
+    }
+}
+
+impl<T: ToWren> ToWren for Option<T> {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        match self {
+            Some(value) => value.to_wren(vm, slot),
+            None => vm.set_slot_null(slot),
+        }
+    }
+}
+
+impl<T: FromWren> FromWren for Option<T> {
+    fn from_wren(vm: &mut VM, slot: i32) -> Option<Option<T>> {
+        if vm.get_slot_type(slot) == ::Type::Null {
+            Some(None)
+        } else {
+            Some(Some(T::from_wren(vm, slot)?))
+        }
+    }
+}
+
+impl<T: ToWren> ToWren for Vec<T> {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_new_list(slot);
+        for (index, value) in self.into_iter().enumerate() {
+            value.to_wren(vm, slot + 1);
+            vm.insert_in_list(slot, index as i32, slot + 1);
+        }
+    }
+}
+
+impl<T: ToWren + Clone> ToWren for &[T] {
+    fn to_wren(self, vm: &mut VM, slot: i32) {
+        vm.set_slot_new_list(slot);
+        for (index, value) in self.iter().cloned().enumerate() {
+            value.to_wren(vm, slot + 1);
+            vm.insert_in_list(slot, index as i32, slot + 1);
+        }
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($index:tt : $ty:ident),+) => {
+        impl<$($ty: ToWren),+> ToWren for ($($ty,)+) {
+            fn to_wren(self, vm: &mut VM, slot: i32) {
+                $(self.$index.to_wren(vm, slot + $index as i32);)+
+            }
+        }
+    };
+}
+
+impl_tuple!(0: A);
+impl_tuple!(0: A, 1: B);
+impl_tuple!(0: A, 1: B, 2: C);
+impl_tuple!(0: A, 1: B, 2: C, 3: D);
+
+impl VM {
+    /// Writes `value` into `slot` using its `ToWren` impl.
+    ///
+    /// Tuples push each element into consecutive slots starting at `slot`, which is handy right
+    /// before `call` - e.g. `vm.set_slot(1, (1.0, "hi"))` fills slots 1 and 2 at once.
+    pub fn set_slot<T: ToWren>(&mut self, slot: i32, value: T) {
+        value.to_wren(self, slot);
+    }
+
+    /// Reads `slot` using `T`'s `FromWren` impl, returning `None` if it doesn't hold a `T`.
+    pub fn get_slot<T: FromWren>(&mut self, slot: i32) -> Option<T> {
+        T::from_wren(self, slot)
+    }
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use {vm, ErrorType, Handle, InterpretResult, VM};
+
+/// One frame of a Wren stack trace, in the order Wren reported them (outermost call first).
+///
+/// Any compile or runtime error reported after the first one is also stored as a frame, in the
+/// order it arrived, with its message in `message`.
+#[derive(Debug, Clone)]
+pub struct WrenErrorFrame {
+    pub module: String,
+    pub line: i32,
+    pub message: String,
+}
+
+/// A compile or runtime failure captured by `VM::try_interpret`/`try_interpret_in_module`/
+/// `try_call`, built from whatever the VM's `error_fn` reported.
+#[derive(Debug, Clone)]
+pub struct WrenError {
+    pub kind: ErrorType,
+    pub module: String,
+    pub line: i32,
+    pub message: String,
+    pub stack_trace: Vec<WrenErrorFrame>,
+}
+
+#[derive(Default)]
+struct Collector {
+    kind: Option<ErrorType>,
+    module: String,
+    line: i32,
+    message: String,
+    stack_trace: Vec<WrenErrorFrame>,
+}
+
+thread_local! {
+    static COLLECTORS: RefCell<HashMap<usize, Collector>> = RefCell::new(HashMap::new());
+}
+
+// Installed as every `Configuration`'s `error_fn` (see `Configuration::new`), so `try_interpret`
+// et al. work out of the box. Still prints via `default_error` first, so embedders that never
+// touch the `try_*` methods see the same console output as before. `VM::interpret`/
+// `interpret_in_module`/`call` all clear this VM's collector before running (and `Drop` clears it
+// once more), so embedders who never call a `try_*` method don't accumulate any state here.
+pub(crate) fn collecting_error(vm: &mut VM, error_type: ErrorType, module: &str, line: i32, message: &str) {
+    vm::default_error(vm, error_type, module, line, message);
+
+    let key = vm.raw_ptr() as usize;
+    COLLECTORS.with(|collectors| {
+        let mut collectors = collectors.borrow_mut();
+        let collector = collectors.entry(key).or_insert_with(Collector::default);
+        match error_type {
+            ErrorType::Compile | ErrorType::Runtime => {
+                if collector.kind.is_none() {
+                    collector.kind = Some(error_type);
+                    collector.module = module.to_owned();
+                    collector.line = line;
+                    collector.message = message.to_owned();
+                } else {
+                    collector.stack_trace.push(WrenErrorFrame {
+                        module: module.to_owned(),
+                        line,
+                        message: message.to_owned(),
+                    });
+                }
+            }
+            ErrorType::StackTrace => collector.stack_trace.push(WrenErrorFrame {
+                module: module.to_owned(),
+                line,
+                message: message.to_owned(),
+            }),
+        }
+    });
+}
+
+pub(crate) fn clear(vm: &mut VM) {
+    let key = vm.raw_ptr() as usize;
+    COLLECTORS.with(|collectors| {
+        collectors.borrow_mut().remove(&key);
+    });
+}
+
+fn take(vm: &mut VM) -> WrenError {
+    let key = vm.raw_ptr() as usize;
+    let collector = COLLECTORS
+        .with(|collectors| collectors.borrow_mut().remove(&key))
+        .unwrap_or_default();
+    WrenError {
+        kind: collector.kind.unwrap_or(ErrorType::Runtime),
+        module: collector.module,
+        line: collector.line,
+        message: collector.message,
+        stack_trace: collector.stack_trace,
+    }
+}
+
+impl VM {
+    /// Like `interpret`, but turns a non-success result into a `WrenError` built from whatever
+    /// was reported through this VM's `error_fn` during compilation/execution.
+    ///
+    /// `interpret` itself clears this VM's collected state before running, so there's no need to
+    /// call `clear` again here.
+    pub fn try_interpret(&mut self, source: &str) -> Result<(), WrenError> {
+        match self.interpret(source) {
+            InterpretResult::Success => Ok(()),
+            _ => Err(take(self)),
+        }
+    }
+
+    /// Like `interpret_in_module`, but returns a `WrenError` on failure. See `try_interpret`.
+    pub fn try_interpret_in_module(&mut self, module: &str, source: &str) -> Result<(), WrenError> {
+        match self.interpret_in_module(module, source) {
+            InterpretResult::Success => Ok(()),
+            _ => Err(take(self)),
+        }
+    }
+
+    /// Like `call`, but returns a `WrenError` on failure. See `try_interpret`.
+    pub fn try_call(&mut self, method: &Handle) -> Result<(), WrenError> {
+        match self.call(method) {
+            InterpretResult::Success => Ok(()),
+            _ => Err(take(self)),
+        }
+    }
+}
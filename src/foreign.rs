@@ -0,0 +1,290 @@
+use ffi;
+use libc::c_char;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::ptr;
+use {Configuration, Pointer, VM};
+
+/// Number of foreign methods a single `ModuleRegistry` can hold.
+///
+/// Wren stores whatever `WrenForeignMethodFn` `bind_foreign_method_fn` returns directly in the
+/// class's method table and calls that exact function pointer for the life of the VM, so every
+/// registered method needs a native function address of its own. We pre-generate that many
+/// trampolines below via `dispatch_table!` and hand them out in order as methods are registered;
+/// bump `MAX_METHODS` (and the table literal) together if a project needs more than this.
+///
+/// The closures themselves live in the `ModuleRegistry` being built (ultimately owned by the
+/// `VM`'s `UserData`, freed on `Drop`), not in any global table - `dispatch::<N>` only picks which
+/// fixed function address to hand Wren; at call time it looks up slot `N` in whichever VM is
+/// actually invoking it, via that VM's own `user_data`. So this limit is per-registry, and two
+/// VMs (or two `ModuleRegistry`s built one after another) don't share or exhaust each other's
+/// quota.
+const MAX_METHODS: usize = 32;
+
+type BoxedMethod = Box<dyn Fn(&mut VM)>;
+
+fn call_slot(slot: usize, vm: *mut ffi::WrenVM) {
+    let mut wrapped = unsafe { VM::from_ptr(vm) };
+    // Grab a raw pointer to the boxed closure rather than holding a borrow of `UserData` across
+    // the call: the method itself may re-enter the registry (e.g. interpreting more Wren code).
+    let method: *const BoxedMethod = {
+        let registry = unsafe { registry(vm) };
+        &registry.methods[slot]
+    };
+    unsafe { (*method)(&mut wrapped) };
+}
+
+extern "C" fn dispatch<const N: usize>(vm: *mut ffi::WrenVM) {
+    call_slot(N, vm)
+}
+
+macro_rules! dispatch_table {
+    ($($n:literal),* $(,)?) => {
+        [$(dispatch::<$n>),*]
+    };
+}
+
+const DISPATCH_TABLE: [extern "C" fn(*mut ffi::WrenVM); MAX_METHODS] = dispatch_table![
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+    26, 27, 28, 29, 30, 31,
+];
+
+/// Implemented by Rust types that back a Wren foreign class.
+///
+/// `allocate` runs when Wren executes the class's constructor, with the constructor's arguments
+/// already sitting in slots 1..N. `finalize` runs later, during garbage collection, when no `VM`
+/// is safely reachable, so it only ever touches the value being dropped.
+pub trait ForeignClass: Sized {
+    fn allocate(vm: &mut VM) -> Self;
+
+    fn finalize(&mut self) {}
+}
+
+extern "C" fn allocate_trampoline<T: ForeignClass>(vm: *mut ffi::WrenVM) {
+    let mut vm = unsafe { VM::from_ptr(vm) };
+    let value = T::allocate(&mut vm);
+    let slot = vm.set_slot_new_foreign_typed::<T>(0, 0);
+    unsafe { ptr::write(slot, value) };
+}
+
+extern "C" fn finalize_trampoline<T: ForeignClass>(data: Pointer) {
+    let value = unsafe { &mut *(data as *mut T) };
+    value.finalize();
+    unsafe { ptr::drop_in_place(data as *mut T) };
+}
+
+struct ClassEntry {
+    allocate: ::ForeignMethodFn,
+    finalize: ::FinalizerFn,
+    methods: HashMap<(bool, String), extern "C" fn(*mut ffi::WrenVM)>,
+}
+
+/// Registers the instance and static methods of a single foreign class.
+///
+/// Obtained from `ModuleRegistry::register_class`. Signatures must match Wren's mangled form
+/// exactly, e.g. `"area()"`, `"scaleBy(_)"`, a getter `"x"`, a setter `"x=(_)"`, or a subscript
+/// `"[_]"`.
+pub struct ClassBuilder<'a, T: ForeignClass> {
+    registry: &'a mut ModuleRegistry,
+    module: String,
+    class_name: String,
+    methods: HashMap<(bool, String), extern "C" fn(*mut ffi::WrenVM)>,
+    _marker: ::std::marker::PhantomData<T>,
+}
+
+impl<'a, T: ForeignClass + 'static> ClassBuilder<'a, T> {
+    /// Registers an instance method. `f` receives the foreign object and the calling `VM`, with
+    /// the method's own arguments already sitting in slots 1..N.
+    pub fn add_method<F>(mut self, signature: &str, f: F) -> Self
+    where
+        F: Fn(&mut T, &mut VM) + 'static,
+    {
+        let trampoline = self.registry.register_method(Box::new(move |vm: &mut VM| {
+            // `get_slot_foreign_typed`'s `&mut T` borrows `vm`, but `f` also needs `vm` itself;
+            // go through a raw pointer to end that borrow before calling `f`.
+            let this = unsafe { vm.get_slot_foreign_typed::<T>(0) as *mut T };
+            f(unsafe { &mut *this }, vm);
+        }));
+        self.methods.insert((false, signature.to_owned()), trampoline);
+        self
+    }
+
+    /// Registers a static method.
+    pub fn add_static_method<F>(mut self, signature: &str, f: F) -> Self
+    where
+        F: Fn(&mut VM) + 'static,
+    {
+        let trampoline = self.registry.register_method(Box::new(f));
+        self.methods.insert((true, signature.to_owned()), trampoline);
+        self
+    }
+
+    /// Finishes registering this class with its `ModuleRegistry`.
+    pub fn register(self) {
+        self.registry.classes.insert(
+            (self.module, self.class_name),
+            ClassEntry {
+                allocate: Some(allocate_trampoline::<T>),
+                finalize: Some(finalize_trampoline::<T>),
+                methods: self.methods,
+            },
+        );
+    }
+}
+
+/// Collects the foreign classes registered before a `VM` is created.
+///
+/// Install one with `Configuration::set_module_registry`, which wires up the crate's own
+/// `bind_foreign_class_fn`/`bind_foreign_method_fn` to dispatch into it. This removes the need to
+/// hand-write those callbacks and match on module/class/signature strings by hand.
+#[derive(Default)]
+pub struct ModuleRegistry {
+    classes: HashMap<(String, String), ClassEntry>,
+    methods: Vec<BoxedMethod>,
+}
+
+impl ModuleRegistry {
+    pub fn new() -> ModuleRegistry {
+        ModuleRegistry::default()
+    }
+
+    /// Begins registering a foreign class. Nothing is installed until `ClassBuilder::register`
+    /// is called.
+    pub fn register_class<T: ForeignClass + 'static>(
+        &mut self,
+        module: &str,
+        class_name: &str,
+    ) -> ClassBuilder<'_, T> {
+        ClassBuilder {
+            registry: self,
+            module: module.to_owned(),
+            class_name: class_name.to_owned(),
+            methods: HashMap::new(),
+            _marker: ::std::marker::PhantomData,
+        }
+    }
+
+    // Hands out the next free dispatch slot for this registry and stores `f` to back it.
+    fn register_method(&mut self, f: BoxedMethod) -> extern "C" fn(*mut ffi::WrenVM) {
+        let slot = self.methods.len();
+        assert!(
+            slot < MAX_METHODS,
+            "exceeded the maximum of {} foreign methods in a single ModuleRegistry",
+            MAX_METHODS
+        );
+        self.methods.push(f);
+        DISPATCH_TABLE[slot]
+    }
+}
+
+/// What the crate actually stores as `WrenConfiguration::user_data`/a `VM`'s user data once
+/// anything in this layer is used: the module registry, whatever the embedder's own user data is,
+/// (see `alloc.rs`) an optional memory cap, and the module load/resolve closures registered
+/// through `Configuration::set_load_module_fn`/`set_resolve_module_fn`. Recovered via `Configuration::ensure_user_data`
+/// so features that need a slot here can share one allocation instead of clobbering each other.
+/// Owned by the `VM` it ends up installed on - freed in `VM`'s `Drop` impl, not leaked.
+pub(crate) struct UserData {
+    pub(crate) registry: ModuleRegistry,
+    pub(crate) embedder_data: Pointer,
+    pub(crate) memory_limit: Option<::alloc::Limit>,
+    pub(crate) load_module_fn: Option<::vm::LoadModuleFn>,
+    pub(crate) resolve_module_fn: Option<::vm::ResolveModuleFn>,
+}
+
+impl Configuration {
+    /// Returns the crate's shared `UserData`, allocating it on first use.
+    ///
+    /// Used internally by `set_module_registry` and `set_memory_limit` so both can be used on the
+    /// same `Configuration` without one stomping on the other's `user_data` pointer.
+    pub(crate) fn ensure_user_data(&mut self) -> &mut UserData {
+        if self.user_data_ptr().is_null() {
+            let boxed = Box::new(UserData {
+                registry: ModuleRegistry::new(),
+                embedder_data: ptr::null_mut(),
+                memory_limit: None,
+                load_module_fn: None,
+                resolve_module_fn: None,
+            });
+            self.set_user_data(Box::into_raw(boxed) as Pointer);
+            // So `VM::drop` knows to reclaim this box - see `Configuration::set_owns_user_data`.
+            self.set_owns_user_data();
+        }
+        unsafe { &mut *(self.user_data_ptr() as *mut UserData) }
+    }
+
+    /// Installs `registry`, wiring the crate's own `bind_foreign_class_fn`/`bind_foreign_method_fn`
+    /// so foreign classes declared via `ModuleRegistry::register_class` work without any
+    /// hand-written binding callbacks. Fetch or change the embedder's own user data afterwards
+    /// through `VM::embedder_data`/`set_embedder_data` rather than `get_user_data`/`set_user_data`,
+    /// which now point at the crate's own `UserData` holding the registry.
+    pub fn set_module_registry(&mut self, registry: ModuleRegistry) {
+        self.ensure_user_data().registry = registry;
+        self.set_bind_foreign_class_fn(Some(bind_foreign_class_fn));
+        self.set_bind_foreign_method_fn(Some(bind_foreign_method_fn));
+    }
+}
+
+impl VM {
+    /// The embedder's own user data, as passed to `VM::set_embedder_data` (or left as null).
+    /// Only meaningful once a `ModuleRegistry`, a memory limit, or a module load/resolve closure
+    /// has been installed; use
+    /// `get_user_data`/`set_user_data` otherwise.
+    pub fn embedder_data(&mut self) -> Pointer {
+        unsafe { (*user_data(self)).embedder_data }
+    }
+
+    /// Replaces the embedder data stored alongside the crate's own bookkeeping.
+    pub fn set_embedder_data(&mut self, data: Pointer) {
+        unsafe { (*user_data(self)).embedder_data = data }
+    }
+}
+
+fn user_data(vm: &mut VM) -> *mut UserData {
+    vm.get_user_data() as *mut UserData
+}
+
+unsafe fn registry<'a>(vm: *mut ffi::WrenVM) -> &'a mut ModuleRegistry {
+    let data = ffi::wrenGetUserData(vm) as *mut UserData;
+    &mut (*data).registry
+}
+
+extern "C" fn bind_foreign_class_fn(
+    vm: *mut ffi::WrenVM,
+    module: *const c_char,
+    class_name: *const c_char,
+) -> ffi::WrenForeignClassMethods {
+    let registry = unsafe { registry(vm) };
+    let module = unsafe { CStr::from_ptr(module) }.to_str().unwrap();
+    let class_name = unsafe { CStr::from_ptr(class_name) }.to_str().unwrap();
+    match registry
+        .classes
+        .get(&(module.to_owned(), class_name.to_owned()))
+    {
+        Some(entry) => ffi::WrenForeignClassMethods {
+            allocate: entry.allocate,
+            finalize: entry.finalize,
+        },
+        None => ffi::WrenForeignClassMethods {
+            allocate: None,
+            finalize: None,
+        },
+    }
+}
+
+extern "C" fn bind_foreign_method_fn(
+    vm: *mut ffi::WrenVM,
+    module: *const c_char,
+    class_name: *const c_char,
+    is_static: bool,
+    signature: *const c_char,
+) -> ::ForeignMethodFn {
+    let registry = unsafe { registry(vm) };
+    let module = unsafe { CStr::from_ptr(module) }.to_str().unwrap();
+    let class_name = unsafe { CStr::from_ptr(class_name) }.to_str().unwrap();
+    let signature = unsafe { CStr::from_ptr(signature) }.to_str().unwrap();
+    registry
+        .classes
+        .get(&(module.to_owned(), class_name.to_owned()))
+        .and_then(|entry| entry.methods.get(&(is_static, signature.to_owned())))
+        .copied()
+}
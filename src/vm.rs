@@ -1,8 +1,10 @@
 use ffi;
+use libc;
 use libc::c_char;
 use std::ffi::{CStr, CString};
 use std::io;
 use std::mem;
+use std::ptr;
 use std::rc::Rc;
 use std::slice;
 use {ErrorType, InterpretResult, Pointer, Type};
@@ -11,7 +13,7 @@ fn default_write(_: &mut VM, text: &str) {
     print!("{}", text);
 }
 
-fn default_error(_: &mut VM, _type: ErrorType, module: &str, line: i32, message: &str) {
+pub(crate) fn default_error(_: &mut VM, _type: ErrorType, module: &str, line: i32, message: &str) {
     match _type {
         ErrorType::Compile => println!("[{} line {}] {}", module, line, message),
         ErrorType::Runtime => println!("{}", message),
@@ -49,8 +51,106 @@ fn default_load_module(_: &mut VM, name: &str) -> Option<String> {
     }
 }
 
+// Stored in the owning VM's `foreign::UserData` (see `Configuration::set_load_module_fn`/
+// `set_resolve_module_fn`) rather than a thread-global, so two VMs on the same thread each keep
+// their own loader instead of the second silently overwriting the first's.
+pub(crate) type LoadModuleFn = Box<dyn FnMut(&mut VM, &str) -> Option<String>>;
+pub(crate) type ResolveModuleFn = Box<dyn FnMut(&mut VM, &str, &str) -> Option<String>>;
+
+extern "C" fn load_module_shim(vm: *mut ffi::WrenVM, name: *const c_char) -> ffi::WrenLoadModuleResult {
+    let unresolved = || ffi::WrenLoadModuleResult {
+        source: ptr::null_mut(),
+        on_complete: None,
+        user_data: ptr::null_mut(),
+    };
+    // Unwinding across this `extern "C"` boundary is undefined behavior, so a non-UTF-8 module
+    // name or a module source with an interior NUL is reported as unresolved instead of panicking.
+    let name = match unsafe { CStr::from_ptr(name) }.to_str() {
+        Ok(name) => name,
+        Err(_) => return unresolved(),
+    };
+    let mut wrapped = unsafe { VM::from_ptr(vm) };
+    let data = unsafe { &mut *(ffi::wrenGetUserData(vm) as *mut ::foreign::UserData) };
+    let source = data
+        .load_module_fn
+        .as_mut()
+        .and_then(|f| f(&mut wrapped, name));
+    match source.map(CString::new) {
+        Some(Ok(source)) => ffi::WrenLoadModuleResult {
+            source: source.into_raw(),
+            on_complete: Some(on_load_module_complete),
+            user_data: ptr::null_mut(),
+        },
+        Some(Err(_)) | None => unresolved(),
+    }
+}
+
+// Runs after Wren has finished compiling the module we handed it, so it's safe to reclaim the
+// `CString` we allocated for `result.source` in `load_module_shim` above. This is what fixes the
+// previous leak, where the source string had no opportunity to ever be freed.
+extern "C" fn on_load_module_complete(
+    _vm: *mut ffi::WrenVM,
+    _name: *const c_char,
+    result: ffi::WrenLoadModuleResult,
+) {
+    if !result.source.is_null() {
+        unsafe { drop(CString::from_raw(result.source as *mut c_char)) };
+    }
+}
+
+extern "C" fn resolve_module_shim(
+    vm: *mut ffi::WrenVM,
+    importer: *const c_char,
+    name: *const c_char,
+) -> *const c_char {
+    // Unwinding across this `extern "C"` boundary is undefined behavior, so a non-UTF-8 importer
+    // or module name is reported as unresolved instead of panicking.
+    let importer = match unsafe { CStr::from_ptr(importer) }.to_str() {
+        Ok(importer) => importer,
+        Err(_) => return ptr::null(),
+    };
+    let name = match unsafe { CStr::from_ptr(name) }.to_str() {
+        Ok(name) => name,
+        Err(_) => return ptr::null(),
+    };
+    let mut wrapped = unsafe { VM::from_ptr(vm) };
+    let data = unsafe { &mut *(ffi::wrenGetUserData(vm) as *mut ::foreign::UserData) };
+    let resolved = data
+        .resolve_module_fn
+        .as_mut()
+        .and_then(|f| f(&mut wrapped, importer, name));
+    match resolved {
+        Some(resolved) => {
+            // Unlike `load_module_shim`, there's no `on_complete` callback here: Wren copies the
+            // name into its own string right after this call and then frees what we returned
+            // itself, through whichever `reallocate_fn` is configured. So the pointer has to come
+            // from an allocator that function can free directly - not a `CString` kept alive on
+            // our side, which would be freed twice (once by Wren, once by us). Go through
+            // `alloc::alloc_with_header` when `set_memory_limit` has installed `limited_reallocate`
+            // (which expects every live pointer to carry its own size header), and fall back to a
+            // plain `malloc` - matching Wren's default `reallocate_fn` - otherwise.
+            let bytes = resolved.as_bytes();
+            let raw = match data.memory_limit.as_mut() {
+                Some(limit) => ::alloc::alloc_with_header(limit, bytes),
+                None => {
+                    let raw = unsafe { libc::malloc(bytes.len() + 1) };
+                    if !raw.is_null() {
+                        unsafe {
+                            ptr::copy_nonoverlapping(bytes.as_ptr(), raw as *mut u8, bytes.len());
+                            *(raw as *mut u8).add(bytes.len()) = 0;
+                        }
+                    }
+                    raw
+                }
+            };
+            raw as *const c_char
+        }
+        None => ptr::null(),
+    }
+}
+
 /// Wrapper around `WrenConfiguration`. Refer to `wren.h` for info on each field.
-pub struct Configuration(ffi::WrenConfiguration);
+pub struct Configuration(ffi::WrenConfiguration, bool);
 
 impl Configuration {
     /// Create a new Configuration using `wrenInitConfiguration`.
@@ -63,9 +163,9 @@ impl Configuration {
         let mut raw: ffi::WrenConfiguration =
             unsafe { mem::MaybeUninit::<ffi::WrenConfiguration>::uninit().assume_init() };
         unsafe { ffi::wrenInitConfiguration(&mut raw) }
-        let mut cfg = Configuration(raw);
+        let mut cfg = Configuration(raw, false);
         cfg.set_write_fn(wren_write_fn!(default_write));
-        cfg.set_error_fn(wren_error_fn!(default_error));
+        cfg.set_error_fn(wren_error_fn!(::error::collecting_error));
         cfg
     }
 
@@ -73,8 +173,28 @@ impl Configuration {
         self.0.reallocate_fn = f;
     }
 
-    pub fn set_load_module_fn(&mut self, f: ::LoadModuleFn) {
-        self.0.load_module_fn = f;
+    /// Registers a closure used to load a module's source when Wren imports it.
+    ///
+    /// Returning `None` leaves the module unresolved. The crate owns the `CString` allocation
+    /// backing the source it hands to Wren and frees it itself once Wren is done compiling, via
+    /// `WrenLoadModuleResult::on_complete` - the embedder's closure just deals in `String`.
+    pub fn set_load_module_fn<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut VM, &str) -> Option<String> + 'static,
+    {
+        self.ensure_user_data().load_module_fn = Some(Box::new(f));
+        self.0.load_module_fn = Some(load_module_shim);
+    }
+
+    /// Registers a closure used to canonicalize a relative import, e.g. resolving `"./util"`
+    /// (imported from `importer`) to an absolute module name Wren can use to dedupe loaded
+    /// modules. Returning `None` reports the import as unresolvable.
+    pub fn set_resolve_module_fn<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut VM, &str, &str) -> Option<String> + 'static,
+    {
+        self.ensure_user_data().resolve_module_fn = Some(Box::new(f));
+        self.0.resolve_module_fn = Some(resolve_module_shim);
     }
 
     pub fn set_bind_foreign_method_fn(&mut self, f: ::BindForeignMethodFn) {
@@ -108,6 +228,21 @@ impl Configuration {
     pub fn set_user_data(&mut self, data: Pointer) {
         self.0.user_data = data;
     }
+
+    /// Returns the raw `user_data` pointer currently configured, without taking it.
+    ///
+    /// Crate-internal: lets features that share `user_data` (see `foreign::UserData`) check
+    /// whether a wrapper has already been installed before allocating their own.
+    pub(crate) fn user_data_ptr(&self) -> Pointer {
+        self.0.user_data
+    }
+
+    /// Marks `user_data` as a crate-owned `foreign::UserData` box, so the `VM` built from this
+    /// `Configuration` frees it in `Drop` instead of leaking it. Crate-internal: called by
+    /// `foreign::Configuration::ensure_user_data` right after it allocates the box.
+    pub(crate) fn set_owns_user_data(&mut self) {
+        self.1 = true;
+    }
 }
 
 /// Reference-counted wrapper around `WrenHandle`.
@@ -165,14 +300,20 @@ impl ForeignClassMethods {
 pub struct VM {
     raw: *mut ffi::WrenVM,
     owned: bool,
+    owns_user_data: bool,
 }
 
 impl VM {
     /// Create a new VM.
     pub fn new(cfg: Configuration) -> VM {
         let mut cfg = cfg;
+        let owns_user_data = cfg.1;
         let raw = unsafe { ffi::wrenNewVM(&mut cfg.0) };
-        VM { raw, owned: true }
+        VM {
+            raw,
+            owned: true,
+            owns_user_data,
+        }
     }
 
     /// Create a wrapper around an existing WrenVM pointer.
@@ -182,6 +323,7 @@ impl VM {
         VM {
             raw: ptr,
             owned: false,
+            owns_user_data: false,
         }
     }
 
@@ -192,12 +334,14 @@ impl VM {
 
     /// Maps to `wrenInterpret`.
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        ::error::clear(self);
         let source_cstr = CString::new(source).unwrap();
         unsafe { ffi::wrenInterpret(self.raw, source_cstr.as_ptr()) }
     }
 
     /// Maps to `wrenInterpretInModule`.
     pub fn interpret_in_module(&mut self, module: &str, source: &str) -> InterpretResult {
+        ::error::clear(self);
         let module_cstr = CString::new(module).unwrap();
         let source_cstr = CString::new(source).unwrap();
         unsafe { ffi::wrenInterpretInModule(self.raw, module_cstr.as_ptr(), source_cstr.as_ptr()) }
@@ -226,6 +370,7 @@ impl VM {
 
     /// Maps to `wrenCall`.
     pub fn call(&mut self, method: &Handle) -> InterpretResult {
+        ::error::clear(self);
         unsafe { ffi::wrenCall(self.raw, method.0.raw) }
     }
 
@@ -464,12 +609,35 @@ impl VM {
     pub fn set_user_data(&mut self, data: Pointer) {
         unsafe { ffi::wrenSetUserData(self.raw, data) }
     }
+
+    /// Returns the raw `WrenVM` pointer backing this wrapper.
+    ///
+    /// Crate-internal: used as a per-VM key by features (like `error`'s collected `WrenError`s)
+    /// that need to keep state outside of Wren's own `user_data` slot.
+    pub(crate) fn raw_ptr(&self) -> *mut ffi::WrenVM {
+        self.raw
+    }
 }
 
 impl Drop for VM {
     fn drop(&mut self) {
         if self.owned {
+            // Reclaim this VM's collected-error state (see `error::clear`) before the pointer
+            // backing its key goes away and could be handed to an unrelated VM later.
+            ::error::clear(self);
+            // Grab the `UserData` pointer before `wrenFreeVM`, but don't free the box until
+            // after: `wrenFreeVM` deallocates every object still live in the VM through
+            // `reallocate_fn`, which (via `alloc::limited_reallocate`) reads and updates
+            // `UserData.memory_limit` on each call - freeing the box first is a use-after-free.
+            let user_data = if self.owns_user_data {
+                unsafe { ffi::wrenGetUserData(self.raw) }
+            } else {
+                ptr::null_mut()
+            };
             unsafe { ffi::wrenFreeVM(self.raw) }
+            if !user_data.is_null() {
+                unsafe { drop(Box::from_raw(user_data as *mut ::foreign::UserData)) };
+            }
         }
     }
 }